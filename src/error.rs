@@ -0,0 +1,34 @@
+use std::fmt;
+
+use openssl::error::ErrorStack;
+
+#[derive(Debug)]
+pub struct OpensslEncError {
+    message: String,
+}
+
+impl OpensslEncError {
+    pub fn new(message: &str) -> Self {
+        return OpensslEncError { message: message.to_string() };
+    }
+}
+
+impl fmt::Display for OpensslEncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", self.message);
+    }
+}
+
+impl std::error::Error for OpensslEncError {}
+
+impl From<&str> for OpensslEncError {
+    fn from(message: &str) -> Self {
+        return OpensslEncError::new(message);
+    }
+}
+
+impl From<ErrorStack> for OpensslEncError {
+    fn from(error: ErrorStack) -> Self {
+        return OpensslEncError::new(&error.to_string());
+    }
+}
@@ -0,0 +1,168 @@
+//! `std::io::Read`/`std::io::Write` adapters built on top of [`OpensslEnc`]'s chunk API, so
+//! ciphertext can be piped through `std::io::copy` instead of hand-rolling a read loop.
+
+use std::io::{self, Read, Write};
+
+use crate::error::OpensslEncError;
+use crate::OpensslEnc;
+
+/// Bytes held back from the inner reader before handing anything to `decrypt_chunk`: enough for
+/// the final cipher block (which may carry padding that must only be stripped once EOF is known)
+/// plus the 16-byte `Salted__`+salt header read on the first chunk.
+const HOLD_BACK_LEN_EXTRA: usize = 16;
+
+fn to_io_error(error: OpensslEncError) -> io::Error {
+    return io::Error::new(io::ErrorKind::Other, error);
+}
+
+/// Wraps a writer, encrypting everything written to it with an [`OpensslEnc`] before passing it
+/// on. The magic header is emitted automatically on the first write; call [`Encryptor::finish`]
+/// once done to flush the final (possibly padded) block.
+pub struct Encryptor<W: Write> {
+    openssl_enc: OpensslEnc,
+    inner: W,
+}
+
+impl<W: Write> Encryptor<W> {
+    pub fn new(openssl_enc: OpensslEnc, inner: W) -> Encryptor<W> {
+        return Encryptor { openssl_enc, inner };
+    }
+
+    /// Finishes encryption, writes out any remaining data and returns the wrapped writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let final_data = self.openssl_enc.encrypter_finalize().map_err(to_io_error)?;
+        self.inner.write_all(&final_data)?;
+        self.inner.flush()?;
+        return Ok(self.inner);
+    }
+}
+
+impl<W: Write> Write for Encryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let encrypted = self.openssl_enc.encrypt_chunk(&buf.to_vec()).map_err(to_io_error)?;
+        self.inner.write_all(&encrypted)?;
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.inner.flush();
+    }
+}
+
+/// Wraps a reader, decrypting everything read from it with an [`OpensslEnc`]. Internally buffers
+/// at least one block plus the 16-byte header so short reads from the inner reader don't break
+/// salt extraction, and holds back the final block until EOF so its padding is only stripped once.
+pub struct Decryptor<R: Read> {
+    openssl_enc: OpensslEnc,
+    inner: R,
+    ciphertext_buffer: Vec<u8>,
+    plaintext_buffer: Vec<u8>,
+    finished: bool,
+}
+
+impl<R: Read> Decryptor<R> {
+    pub fn new(openssl_enc: OpensslEnc, inner: R) -> Decryptor<R> {
+        return Decryptor {
+            openssl_enc,
+            inner,
+            ciphertext_buffer: Vec::new(),
+            plaintext_buffer: Vec::new(),
+            finished: false,
+        };
+    }
+
+    fn hold_back_len(&self) -> usize {
+        return self.openssl_enc.block_size() + HOLD_BACK_LEN_EXTRA;
+    }
+
+    /// Reads from the inner reader until more than twice `hold_back_len()` bytes are buffered, or
+    /// the inner reader is at EOF. Returns `true` in the first case and `false` once EOF is reached,
+    /// however many bytes are buffered by then.
+    ///
+    /// Buffering to twice the hold-back length (rather than just past it) guarantees that
+    /// whatever is handed to `decrypt_chunk` is itself at least `hold_back_len()` bytes, which
+    /// is always enough to cover the 16-byte salt header on the first call.
+    fn fill_ciphertext_buffer(&mut self) -> io::Result<bool> {
+        let hold_back_len = self.hold_back_len();
+        let mut chunk = vec![0u8; 4096];
+        while self.ciphertext_buffer.len() <= hold_back_len * 2 {
+            let bytes_read = self.inner.read(&mut chunk)?;
+            if bytes_read == 0 {
+                return Ok(false);
+            }
+            self.ciphertext_buffer.extend_from_slice(&chunk[..bytes_read]);
+        }
+        return Ok(true);
+    }
+}
+
+impl<R: Read> Read for Decryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.plaintext_buffer.is_empty() && !self.finished {
+            if self.fill_ciphertext_buffer()? {
+                let usable = self.ciphertext_buffer.len() - self.hold_back_len();
+                let chunk: Vec<u8> = self.ciphertext_buffer.drain(..usable).collect();
+                let plain_text = self.openssl_enc.decrypt_chunk(&chunk).map_err(to_io_error)?;
+                self.plaintext_buffer.extend(plain_text);
+            } else {
+                self.finished = true;
+                let remaining = std::mem::take(&mut self.ciphertext_buffer);
+                let mut plain_text = self.openssl_enc.decrypt_chunk(&remaining).map_err(to_io_error)?;
+                plain_text.extend(self.openssl_enc.decrypter_finalize().map_err(to_io_error)?);
+                self.plaintext_buffer.extend(plain_text);
+            }
+        }
+
+        let copy_len = std::cmp::min(buf.len(), self.plaintext_buffer.len());
+        buf[..copy_len].copy_from_slice(&self.plaintext_buffer[..copy_len]);
+        self.plaintext_buffer.drain(..copy_len);
+        return Ok(copy_len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KdfMode, Digest};
+    use openssl::symm::Cipher;
+
+    #[test]
+    fn can_round_trip_through_encryptor_and_decryptor() {
+        let openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
+        let mut encryptor = Encryptor::new(openssl_encrypt, Vec::new());
+        encryptor.write_all(b"some data").unwrap();
+        let ciphertext = encryptor.finish().unwrap();
+
+        let openssl_decrypt = OpensslEnc::new_for_decrypt("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
+        let mut decryptor = Decryptor::new(openssl_decrypt, &ciphertext[..]);
+        let mut plaintext = Vec::new();
+        io::copy(&mut decryptor, &mut plaintext).unwrap();
+        assert_eq!(b"some data", &plaintext[..]);
+    }
+
+    #[test]
+    fn can_round_trip_through_decryptor_with_byte_at_a_time_reads() {
+        let openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
+        let mut encryptor = Encryptor::new(openssl_encrypt, Vec::new());
+        encryptor.write_all(b"some longer data that spans a couple of cipher blocks").unwrap();
+        let ciphertext = encryptor.finish().unwrap();
+
+        struct OneByteAtATime<'a>(&'a [u8]);
+        impl<'a> Read for OneByteAtATime<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0.is_empty() || buf.is_empty() {
+                    return Ok(0);
+                }
+                buf[0] = self.0[0];
+                self.0 = &self.0[1..];
+                return Ok(1);
+            }
+        }
+
+        let openssl_decrypt = OpensslEnc::new_for_decrypt("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
+        let mut decryptor = Decryptor::new(openssl_decrypt, OneByteAtATime(&ciphertext));
+        let mut plaintext = Vec::new();
+        io::copy(&mut decryptor, &mut plaintext).unwrap();
+        assert_eq!(b"some longer data that spans a couple of cipher blocks", &plaintext[..]);
+    }
+}
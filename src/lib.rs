@@ -1,10 +1,10 @@
 #![crate_name = "openssl_enc"]
-//! An Implementation of openssl enc functionality. 
-//! 
-//! This library encrypts and decrypts just like openssl enc on the command line. 
-//! Allowing you to encrypt with this library and then decrypt with openssl on the other end. or vice versa. 
-//! 
-//! 
+//! An Implementation of openssl enc functionality.
+//!
+//! This library encrypts and decrypts just like openssl enc on the command line.
+//! Allowing you to encrypt with this library and then decrypt with openssl on the other end. or vice versa.
+//!
+//!
 //! # Examples
 //!
 //! Encrypt data in chunks
@@ -13,13 +13,13 @@
 //!     use std::fs::File;
 //!     use std::io::prelude::*;
 //!     use openssl::symm::Cipher;
-//!     use openssl_enc::OpensslEnc;
-//! 
+//!     use openssl_enc::{OpensslEnc, KdfMode, Digest};
+//!
 //!     let mut file_chunk_buf = vec![0u8; 1024];
 //!     let mut file = File::open("test.txt").unwrap();
 //!     let mut out_file = File::create("out.enc").unwrap();
-//!     let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000).unwrap();
-//!     
+//!     let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
+//!
 //!     loop {
 //!       let bytes_read = file.read(&mut file_chunk_buf).unwrap();
 //!       file_chunk_buf.truncate(bytes_read);
@@ -33,16 +33,40 @@
 //!     out_file.write(&final_data).unwrap();
 //!     out_file.flush().unwrap();
 //! ```
-//! 
-//!  Then outside of this to decrypt with openssl you can run. 
+//!
+//!  Then outside of this to decrypt with openssl you can run.
 //!  ```bash
 //!   openssl enc -p -d -aes-256-cbc -md SHA256 -pbkdf2 -iter 10000 -in out.enc -out out.txt
 //!  ```
 //!
+//! Decrypting a file that was produced elsewhere (e.g. by `openssl enc` itself) works the same
+//! way, except the salt embedded in the ciphertext is not known ahead of time. Use
+//! [`OpensslEnc::new_for_decrypt`] to defer key/iv derivation until that salt has been read off
+//! the ciphertext:
+//!
+//! ```no_run
+//!     use std::fs::File;
+//!     use std::io::prelude::*;
+//!     use openssl::symm::Cipher;
+//!     use openssl_enc::{OpensslEnc, KdfMode, Digest};
+//!
+//!     let mut file = File::open("out.enc").unwrap();
+//!     let mut ciphertext = Vec::new();
+//!     file.read_to_end(&mut ciphertext).unwrap();
+//!
+//!     let mut openssl_decrypt = OpensslEnc::new_for_decrypt("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
+//!     let decrypted_data = openssl_decrypt.decrypt(&ciphertext).unwrap();
+//! ```
+//!
+//! The [`io::Encryptor`] and [`io::Decryptor`] adapters wrap the chunk API in `std::io::Write`/
+//! `std::io::Read` so files can be piped through `std::io::copy` instead of hand-rolling the
+//! read loop above.
+//!
 //! see each method in OpensslEnc for individual usage.
 
-use openssl::symm::{encrypt, decrypt, Cipher, Crypter, Mode};
+use openssl::symm::{encrypt, decrypt, encrypt_aead, decrypt_aead, Cipher, Crypter, Mode};
 use openssl::rand::rand_bytes;
+use openssl::hash::{Hasher, MessageDigest};
 
 use ring::{pbkdf2};
 use std::{num::NonZeroU32};
@@ -50,11 +74,84 @@ use std::{num::NonZeroU32};
 mod error;
 use error::OpensslEncError;
 
-static PBKDF2_ALG: pbkdf2::Algorithm = pbkdf2::PBKDF2_HMAC_SHA256;
+pub mod io;
+
+/// Message digest used by a [`KdfMode`], matching the `-md` flag of `openssl enc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Digest {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl Digest {
+    fn message_digest(&self) -> MessageDigest {
+        return match self {
+            Digest::Md5 => MessageDigest::md5(),
+            Digest::Sha1 => MessageDigest::sha1(),
+            Digest::Sha256 => MessageDigest::sha256(),
+            Digest::Sha512 => MessageDigest::sha512(),
+        };
+    }
+
+    fn pbkdf2_algorithm(&self) -> Result<pbkdf2::Algorithm, OpensslEncError> {
+        return match self {
+            Digest::Sha1 => Ok(pbkdf2::PBKDF2_HMAC_SHA1),
+            Digest::Sha256 => Ok(pbkdf2::PBKDF2_HMAC_SHA256),
+            Digest::Sha512 => Ok(pbkdf2::PBKDF2_HMAC_SHA512),
+            Digest::Md5 => Err(OpensslEncError::new("md5 is not supported as a pbkdf2 digest")),
+        };
+    }
+}
+
+/// How `key`/`iv` are derived from `password`/`salt`, matching `openssl enc`'s `-pbkdf2`/`-md` flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KdfMode {
+    /// PBKDF2-HMAC, as used when `-pbkdf2` is passed to `openssl enc`.
+    Pbkdf2 { digest: Digest },
+    /// OpenSSL's legacy `EVP_BytesToKey` derivation, used when `-pbkdf2` is omitted.
+    LegacyBytesToKey { digest: Digest },
+}
+
+/// Derives `key_and_iv_length` bytes of key material from `password`/`salt` using OpenSSL's
+/// legacy `EVP_BytesToKey` algorithm: starting from an empty digest block, each block is
+/// `HASH(previous_block || password || salt)`, re-hashed `iterations - 1` more times, and blocks
+/// are concatenated until enough bytes have been produced.
+fn evp_bytes_to_key(digest: MessageDigest, password: &[u8], salt: &[u8], iterations: u32, key_and_iv_length: usize) -> Result<Vec<u8>, OpensslEncError> {
+    let mut derived = Vec::with_capacity(key_and_iv_length);
+    let mut previous_block: Vec<u8> = Vec::new();
+
+    while derived.len() < key_and_iv_length {
+        let mut hasher = Hasher::new(digest)?;
+        hasher.update(&previous_block)?;
+        hasher.update(password)?;
+        hasher.update(salt)?;
+        let mut block = hasher.finish()?.to_vec();
+
+        for _ in 1..iterations.max(1) {
+            let mut hasher = Hasher::new(digest)?;
+            hasher.update(&block)?;
+            block = hasher.finish()?.to_vec();
+        }
+
+        derived.extend_from_slice(&block);
+        previous_block = block;
+    }
+
+    derived.truncate(key_and_iv_length);
+    return Ok(derived);
+}
+
+/// Length in bytes of the authentication tag OpenSSL's `enc` appends after AEAD ciphertext.
+const AEAD_TAG_LEN: usize = 16;
 
 pub struct OpensslEnc {
-    key: Vec<u8>,
-    iv: Vec<u8>,
+    password: Vec<u8>,
+    iterations: NonZeroU32,
+    kdf_mode: KdfMode,
+    key: Option<Vec<u8>>,
+    iv: Option<Vec<u8>>,
     magic_header: Vec<u8>,
     cipher: Cipher,
     block_size: usize,
@@ -62,6 +159,37 @@ pub struct OpensslEnc {
     decrypter: Option<openssl::symm::Crypter>,
     add_magic_header: bool,
     remove_magic_header: bool,
+    aad: Vec<u8>,
+    pending_tag_bytes: Vec<u8>,
+    salted: bool,
+}
+
+/// Decodes a hex string (as produced by `openssl enc -S`/`-K`/`-iv`) into raw bytes.
+fn decode_hex(hex: &str) -> Result<Vec<u8>, OpensslEncError> {
+    if hex.len() % 2 != 0 {
+        return Err(OpensslEncError::new("hex string must have an even number of characters"));
+    }
+
+    let mut bytes = Vec::with_capacity(hex.len() / 2);
+    for byte_chars in hex.as_bytes().chunks(2) {
+        let byte_str = std::str::from_utf8(byte_chars).map_err(|_| OpensslEncError::new("invalid hex string"))?;
+        let byte = u8::from_str_radix(byte_str, 16).map_err(|_| OpensslEncError::new("invalid hex string"))?;
+        bytes.push(byte);
+    }
+
+    return Ok(bytes);
+}
+
+/// Returns whether `cipher` is an AEAD mode (AES-GCM or ChaCha20-Poly1305), which need
+/// authentication tag handling that CBC-style ciphers don't.
+fn is_aead_cipher(cipher: Cipher) -> bool {
+    let aead_ciphers = [
+        Cipher::aes_128_gcm(),
+        Cipher::aes_192_gcm(),
+        Cipher::aes_256_gcm(),
+        Cipher::chacha20_poly1305(),
+    ];
+    return aead_ciphers.iter().any(|aead_cipher| aead_cipher.as_ptr() == cipher.as_ptr());
 }
 
 trait GetRandomBytes {
@@ -76,15 +204,9 @@ trait GetRandomBytes {
 impl GetRandomBytes for OpensslEnc {}
 
 impl OpensslEnc {
-    /// Creates a new instance of the OpensslEnc struct. 
+    /// Creates a new instance of the OpensslEnc struct.
     /// Creates iv, key, magic_header and other things needed for encryption/decryption
-    pub fn new(password: String, cipher: Cipher, iteration_count: u32) -> Result<OpensslEnc, OpensslEncError> {
-        let iv_length = cipher.iv_len().ok_or("failed to get iv length")?;
-        let key_length = cipher.key_len();
-
-        let key_and_iv_length = iv_length + key_length;
-        let mut pbkdf2_key_iv = vec![0; key_and_iv_length];
-        
+    pub fn new(password: String, cipher: Cipher, iteration_count: u32, kdf_mode: KdfMode) -> Result<OpensslEnc, OpensslEncError> {
         let password_vec = password.as_bytes().to_vec();
         let iterations = NonZeroU32::new(Some(iteration_count).unwrap_or(10000)).ok_or("failed to get iteration_count")?;
         let salt = match OpensslEnc::get_random_bytes(8) {
@@ -92,32 +214,151 @@ impl OpensslEnc {
              Err(error) => return Err(error),
         };
 
-        // might want to wrap this panic. 
-        pbkdf2::derive(PBKDF2_ALG, iterations,  &salt, &password_vec, &mut pbkdf2_key_iv);
+        let mut openssl_enc = OpensslEnc {
+            password: password_vec,
+            iterations,
+            kdf_mode,
+            key: None,
+            iv: None,
+            magic_header: ["Salted__".as_bytes(), &salt].concat(),
+            cipher,
+            block_size: cipher.block_size(),
+            encrypter: None,
+            decrypter: None,
+            add_magic_header: true,
+            remove_magic_header: true,
+            aad: Vec::new(),
+            pending_tag_bytes: Vec::new(),
+            salted: true,
+        };
+        openssl_enc.derive_key_iv(&salt)?;
 
-        let key = pbkdf2_key_iv[0..key_length].to_vec();
-        let iv = pbkdf2_key_iv[key_length..key_and_iv_length].to_vec();
+        return Ok(openssl_enc);
+    }
+
+    /// Creates a new instance of the OpensslEnc struct for decrypting ciphertext whose salt isn't
+    /// known up front, e.g. a file produced by `openssl enc` (or another process) rather than by
+    /// this instance. Key/iv derivation is deferred: the salt is instead read out of the first 16
+    /// bytes of the ciphertext (the `Salted__` magic marker followed by the 8-byte salt) the first
+    /// time `decrypt` or `decrypt_chunk` is called.
+    pub fn new_for_decrypt(password: String, cipher: Cipher, iteration_count: u32, kdf_mode: KdfMode) -> Result<OpensslEnc, OpensslEncError> {
+        let password_vec = password.as_bytes().to_vec();
+        let iterations = NonZeroU32::new(Some(iteration_count).unwrap_or(10000)).ok_or("failed to get iteration_count")?;
 
         return Ok(OpensslEnc {
-            key, 
-            iv,
-            magic_header: ["Salted__".as_bytes(), &salt].concat(),
-            cipher, 
+            password: password_vec,
+            iterations,
+            kdf_mode,
+            key: None,
+            iv: None,
+            magic_header: Vec::new(),
+            cipher,
             block_size: cipher.block_size(),
             encrypter: None,
             decrypter: None,
             add_magic_header: true,
             remove_magic_header: true,
+            aad: Vec::new(),
+            pending_tag_bytes: Vec::new(),
+            salted: true,
         });
     }
 
+    /// Derives `key`/`iv` from `password` and `salt` according to `self.kdf_mode`, storing the
+    /// result so subsequent encrypt/decrypt calls no longer need to know the salt.
+    fn derive_key_iv(&mut self, salt: &[u8]) -> Result<(), OpensslEncError> {
+        let iv_length = self.cipher.iv_len().ok_or("failed to get iv length")?;
+        let key_length = self.cipher.key_len();
+        let key_and_iv_length = iv_length + key_length;
+
+        let key_and_iv = match self.kdf_mode {
+            KdfMode::Pbkdf2 { digest } => {
+                let algorithm = digest.pbkdf2_algorithm()?;
+                let mut derived = vec![0; key_and_iv_length];
+                // might want to wrap this panic.
+                pbkdf2::derive(algorithm, self.iterations, salt, &self.password, &mut derived);
+                derived
+            },
+            KdfMode::LegacyBytesToKey { digest } => {
+                evp_bytes_to_key(digest.message_digest(), &self.password, salt, self.iterations.get(), key_and_iv_length)?
+            },
+        };
+
+        self.key = Some(key_and_iv[0..key_length].to_vec());
+        self.iv = Some(key_and_iv[key_length..key_and_iv_length].to_vec());
+
+        return Ok(());
+    }
+
+    fn is_aead(&self) -> bool {
+        return is_aead_cipher(self.cipher);
+    }
+
+    pub(crate) fn block_size(&self) -> usize {
+        return self.block_size;
+    }
+
+    /// Pins the salt to `salt_hex` (a hex string, matching `openssl enc -S`) instead of a
+    /// randomly generated one, re-deriving `key`/`iv` from it and emitting/expecting the
+    /// corresponding `Salted__` header.
+    pub fn with_salt(mut self, salt_hex: &str) -> Result<OpensslEnc, OpensslEncError> {
+        let salt = decode_hex(salt_hex)?;
+        if salt.len() != 8 {
+            return Err(OpensslEncError::new("salt must be exactly 8 bytes (16 hex characters)"));
+        }
+
+        self.salted = true;
+        self.magic_header = ["Salted__".as_bytes(), &salt].concat();
+        self.derive_key_iv(&salt)?;
+
+        return Ok(self);
+    }
+
+    /// Pins `key`/`iv` directly instead of deriving them from a password, matching `openssl enc
+    /// -K <key> -iv <iv>`. Each must match `self.cipher`'s expected length.
+    pub fn with_raw_key_iv(mut self, key: &[u8], iv: &[u8]) -> Result<OpensslEnc, OpensslEncError> {
+        let expected_key_len = self.cipher.key_len();
+        let expected_iv_len = self.cipher.iv_len().ok_or("failed to get iv length")?;
+
+        if key.len() != expected_key_len {
+            return Err(OpensslEncError::new("key length does not match the cipher"));
+        }
+        if iv.len() != expected_iv_len {
+            return Err(OpensslEncError::new("iv length does not match the cipher"));
+        }
+
+        self.key = Some(key.to_vec());
+        self.iv = Some(iv.to_vec());
+
+        return Ok(self);
+    }
+
+    /// Disables salting entirely, matching `openssl enc -nosalt`: no `Salted__` header is
+    /// emitted or expected, and `key`/`iv` (if not already pinned via [`OpensslEnc::with_raw_key_iv`])
+    /// are derived from the password with an empty salt.
+    pub fn no_salt(mut self) -> Result<OpensslEnc, OpensslEncError> {
+        self.salted = false;
+        self.magic_header = Vec::new();
+        if self.key.is_none() || self.iv.is_none() {
+            self.derive_key_iv(&[])?;
+        }
+
+        return Ok(self);
+    }
+
+    /// Sets additional authenticated data (AAD) to bind to the ciphertext when using an AEAD
+    /// cipher (AES-GCM or ChaCha20-Poly1305). Has no effect for non-AEAD ciphers.
+    pub fn set_aad(&mut self, aad: &[u8]) {
+        self.aad = aad.to_vec();
+    }
+
     /// Encrypts data in one go and retuns the encrypted data.
     /// # Examples
     /// ``` no_run
     ///  use openssl::symm::Cipher;
-    ///  use openssl_enc::OpensslEnc;
-    /// 
-    ///  let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000).unwrap();
+    ///  use openssl_enc::{OpensslEnc, KdfMode, Digest};
+    ///
+    ///  let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
     ///  let encrypted_data = openssl_encrypt.encrypt(&"some data".as_bytes().to_vec()).unwrap();
     ///  assert_eq!(
     ///      b"\x53\x61\x6c\x74\x65\x64\x5f\x5f\x53\x61\x23\x11\x23\x56\x74\x12\x72\x30\x32\x8f\xca\x92\x3c\x3b\x53\x99\x11\x99\x14\x32\x79\x78".to_vec(),
@@ -125,22 +366,31 @@ impl OpensslEnc {
     ///  );
     /// ```
     pub fn encrypt(&mut self, data: &Vec<u8>) -> Result<Vec<u8>, OpensslEncError> {
+        let key = self.key.as_ref().ok_or("key has not been derived")?;
+        let iv = self.iv.as_ref().ok_or("iv has not been derived")?;
+
+        if self.is_aead() {
+            let mut tag = [0u8; AEAD_TAG_LEN];
+            let ciphertext = encrypt_aead(self.cipher, key, Some(iv), &self.aad, &data, &mut tag)?;
+            return Ok([&self.magic_header[..], &ciphertext[..], &tag[..]].concat());
+        }
+
         let ciphertext = encrypt(
             self.cipher,
-            &self.key,
-            Some(&self.iv),
+            key,
+            Some(iv),
             &data)?;
-        
+
         return Ok([&self.magic_header[..], &ciphertext[..]].concat());
     }
 
-    /// Encrypts the data one chunk at a time.  
+    /// Encrypts the data one chunk at a time.
     /// # Examples
     /// ``` no_run
     ///   use openssl::symm::Cipher;
-    ///   use openssl_enc::OpensslEnc;
-    /// 
-    ///   let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000).unwrap();
+    ///   use openssl_enc::{OpensslEnc, KdfMode, Digest};
+    ///
+    ///   let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
     ///   let encrypted_chunk1 = openssl_encrypt.encrypt_chunk(&"some".as_bytes().to_vec()).unwrap();
     ///   let encrypted_chunk2 = openssl_encrypt.encrypt_chunk(&" ".as_bytes().to_vec()).unwrap();
     ///   let encrypted_chunk3 = openssl_encrypt.encrypt_chunk(&"data".as_bytes().to_vec()).unwrap();
@@ -152,17 +402,23 @@ impl OpensslEnc {
     /// ```
     pub fn encrypt_chunk(&mut self, chunk: &Vec<u8>) -> Result<Vec<u8>, OpensslEncError> {
         if self.add_magic_header {
-            self.encrypter = Some(Crypter::new(
+            let key = self.key.as_ref().ok_or("key has not been derived")?;
+            let iv = self.iv.as_ref().ok_or("iv has not been derived")?;
+            let mut encrypter = Crypter::new(
                 self.cipher,
                 Mode::Encrypt,
-                &self.key,
-                Some(&self.iv))?);
+                key,
+                Some(iv))?;
+            if self.is_aead() && !self.aad.is_empty() {
+                encrypter.aad_update(&self.aad)?;
+            }
+            self.encrypter = Some(encrypter);
         }
         let mut ciphertext = vec![0; chunk.len() + self.block_size];
 
         let encrypter = match self.encrypter.as_mut() {
             Some(encrypter) => encrypter,
-            None => { 
+            None => {
                 let no_encrypter_error = OpensslEncError::new("could not get encrypter");
                 return Err(no_encrypter_error);
             },
@@ -178,50 +434,88 @@ impl OpensslEnc {
         }
     }
 
-    /// Finishes the encryption process, returning any remaining data  
+    /// Finishes the encryption process, returning any remaining data. For an AEAD cipher this
+    /// also appends the 16-byte authentication tag, matching the layout OpenSSL's `enc` uses.
     pub fn encrypter_finalize(&mut self) -> Result<Vec<u8>, OpensslEncError> {
         self.add_magic_header = true;
+        let is_aead = self.is_aead();
         let mut ciphertext = vec![0; self.block_size];
         let encrypter = match self.encrypter.as_mut() {
             Some(encrypter) => encrypter,
-            None => { 
+            None => {
                 let no_encrypter_error = OpensslEncError::new("could not get encrypter");
                 return Err(no_encrypter_error);
             },
         };
         let final_length = encrypter.finalize(&mut ciphertext)?;
         ciphertext.truncate(final_length);
+
+        if is_aead {
+            let mut tag = [0u8; AEAD_TAG_LEN];
+            encrypter.get_tag(&mut tag)?;
+            ciphertext.extend_from_slice(&tag);
+        }
+
         return Ok(ciphertext);
     }
 
     /// Decrypts data in one go and retuns the decrypted data.
+    ///
+    /// If the key/iv have not been derived yet (i.e. this instance was created with
+    /// [`OpensslEnc::new_for_decrypt`]), the salt is read out of the first 16 bytes of `data`
+    /// before decrypting.
     /// # Examples
     /// ```no_run
     ///   use openssl::symm::Cipher;
-    ///   use openssl_enc::OpensslEnc;
-    /// 
-    ///   let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000).unwrap();
+    ///   use openssl_enc::{OpensslEnc, KdfMode, Digest};
+    ///
+    ///   let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
     ///   let encrypted_data = b"\x53\x61\x6c\x74\x65\x64\x5f\x5f\x53\x61\x23\x11\x23\x56\x74\x12\x72\x30\x32\x8f\xca\x92\x3c\x3b\x53\x99\x11\x99\x14\x32\x79\x78".to_vec();
     ///   let decrypted_data = openssl_encrypt.decrypt(&encrypted_data).unwrap();
     ///   assert_eq!(b"some data", &decrypted_data[..]);
     /// ```
     pub fn decrypt(&mut self, data: &Vec<u8>) -> Result<Vec<u8>, OpensslEncError> {
-        let data_without_magic_header = &data[16..];
+        let header_len = if self.salted { 16 } else { 0 };
+        if self.key.is_none() || self.iv.is_none() {
+            let salt = if self.salted { &data[8..16] } else { &[][..] };
+            self.derive_key_iv(salt)?;
+        }
+
+        let key = self.key.as_ref().ok_or("key has not been derived")?;
+        let iv = self.iv.as_ref().ok_or("iv has not been derived")?;
+        let data_without_magic_header = &data[header_len..];
+
+        if self.is_aead() {
+            if data_without_magic_header.len() < AEAD_TAG_LEN {
+                return Err(OpensslEncError::new("ciphertext is too short to contain an authentication tag"));
+            }
+            let tag_offset = data_without_magic_header.len() - AEAD_TAG_LEN;
+            let ciphertext = &data_without_magic_header[..tag_offset];
+            let tag = &data_without_magic_header[tag_offset..];
+            let decrypted_data = decrypt_aead(self.cipher, key, Some(iv), &self.aad, ciphertext, tag)
+                .map_err(|_| OpensslEncError::new("authentication failed: ciphertext tag did not verify"))?;
+            return Ok(decrypted_data);
+        }
+
         let decrypted_data = decrypt(
             self.cipher,
-            &self.key,
-            Some(&self.iv),
+            key,
+            Some(iv),
             &data_without_magic_header)?;
         return Ok(decrypted_data);
     }
 
     /// Encrypts the data one chunk at a time.
-    /// # Examples  
+    ///
+    /// If the key/iv have not been derived yet (i.e. this instance was created with
+    /// [`OpensslEnc::new_for_decrypt`]), the salt is read out of the first 16 bytes of the first
+    /// chunk before decrypting it.
+    /// # Examples
     /// ```no_run
     ///   use openssl::symm::Cipher;
-    ///   use openssl_enc::OpensslEnc;
-    /// 
-    ///   let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000).unwrap();
+    ///   use openssl_enc::{OpensslEnc, KdfMode, Digest};
+    ///
+    ///   let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
     ///   let decrypted_chunk1 = openssl_encrypt.decrypt_chunk(&b"\x53\x61\x6c\x74\x65\x64\x5f\x5f\x53\x61\x23\x11\x23\x56\x74\x12\x72".to_vec()).unwrap();
     ///   let decrypted_chunk2 = openssl_encrypt.decrypt_chunk(&b"\x30\x32\x8f\xca\x92\x3c\x3b\x53\x99\x11".to_vec()).unwrap();
     ///   let decrypted_chunk3 = openssl_encrypt.decrypt_chunk(&b"\x99\x14\x32\x79\x78".to_vec()).unwrap();
@@ -234,45 +528,85 @@ impl OpensslEnc {
     pub fn decrypt_chunk(&mut self, chunk: &Vec<u8>) -> Result<Vec<u8>, OpensslEncError> {
         let reformatted_data;
         if self.remove_magic_header {
-            self.decrypter = Some(Crypter::new(
+            let header_len = if self.salted { 16 } else { 0 };
+            if self.key.is_none() || self.iv.is_none() {
+                let salt = if self.salted { &chunk[8..16] } else { &[][..] };
+                self.derive_key_iv(salt)?;
+            }
+
+            let key = self.key.as_ref().ok_or("key has not been derived")?;
+            let iv = self.iv.as_ref().ok_or("iv has not been derived")?;
+            let mut decrypter = Crypter::new(
                 self.cipher,
                 Mode::Decrypt,
-                &self.key,
-                Some(&self.iv))?
-            );
+                key,
+                Some(iv))?;
+            if self.is_aead() && !self.aad.is_empty() {
+                decrypter.aad_update(&self.aad)?;
+            }
+            self.decrypter = Some(decrypter);
             self.remove_magic_header = false;
-            reformatted_data = &chunk[16..];
+            reformatted_data = &chunk[header_len..];
         } else {
             reformatted_data = chunk;
         }
 
-        let mut plain_text = vec![0; reformatted_data.len() + self.block_size];
+        // For an AEAD cipher the last 16 bytes of the stream are the authentication tag, not
+        // ciphertext, but we don't know we've reached them until the stream ends. So hold back
+        // the trailing 16 bytes seen so far and only decrypt what's left over.
+        let data_to_decrypt: Vec<u8> = if self.is_aead() {
+            self.pending_tag_bytes.extend_from_slice(reformatted_data);
+            if self.pending_tag_bytes.len() <= AEAD_TAG_LEN {
+                return Ok(Vec::new());
+            }
+            let split_at = self.pending_tag_bytes.len() - AEAD_TAG_LEN;
+            self.pending_tag_bytes.drain(..split_at).collect()
+        } else {
+            reformatted_data.to_vec()
+        };
+
+        let mut plain_text = vec![0; data_to_decrypt.len() + self.block_size];
 
         let decrypter = match self.decrypter.as_mut() {
             Some(decrypter) => decrypter,
-            None => { 
+            None => {
                 let no_decrypter_error = OpensslEncError::new("could not get decrypter");
                 return Err(no_decrypter_error);
             },
         };
-        let count = decrypter.update(&reformatted_data, &mut plain_text)?;
+        let count = decrypter.update(&data_to_decrypt, &mut plain_text)?;
         plain_text.truncate(count);
 
         return Ok(plain_text);
     }
 
-    /// Finishes the decryption process, returning any remaining data  
+    /// Finishes the decryption process, returning any remaining data. For an AEAD cipher this
+    /// first sets the authentication tag (buffered off the end of the ciphertext stream by
+    /// `decrypt_chunk`) and surfaces a distinct error if it fails to verify.
     pub fn decrypter_finalize(&mut self) -> Result<Vec<u8>, OpensslEncError> {
         self.remove_magic_header = true;
+        let is_aead = self.is_aead();
         let mut ciphertext = vec![0; self.block_size];
         let decrypter = match self.decrypter.as_mut() {
             Some(decrypter) => decrypter,
-            None => { 
+            None => {
                 let no_decrypter_error = OpensslEncError::new("could not get decrypter");
                 return Err(no_decrypter_error);
             },
         };
-        let final_length = decrypter.finalize(&mut ciphertext)?;
+
+        if is_aead {
+            let tag = std::mem::take(&mut self.pending_tag_bytes);
+            decrypter.set_tag(&tag)?;
+        }
+
+        let final_length = match decrypter.finalize(&mut ciphertext) {
+            Ok(final_length) => final_length,
+            Err(_) if is_aead => {
+                return Err(OpensslEncError::new("authentication failed: ciphertext tag did not verify"));
+            },
+            Err(error) => return Err(error.into()),
+        };
         ciphertext.truncate(final_length);
         return Ok(ciphertext);
     }
@@ -292,7 +626,7 @@ mod tests {
 
     #[test]
     fn can_encrypt_correctly() {
-        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000).unwrap();
+        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
         let encrypted_data = openssl_encrypt.encrypt(&"some data".as_bytes().to_vec()).unwrap();
         assert_eq!(
             b"\x53\x61\x6c\x74\x65\x64\x5f\x5f\x53\x61\x23\x11\x23\x56\x74\x12\x72\x30\x32\x8f\xca\x92\x3c\x3b\x53\x99\x11\x99\x14\x32\x79\x78".to_vec(),
@@ -301,7 +635,7 @@ mod tests {
     }
     #[test]
     fn can_encrypt_128_correctly() {
-        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_128_cbc(), 10000).unwrap();
+        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_128_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
         let encrypted_data = openssl_encrypt.encrypt(&"some data".as_bytes().to_vec()).unwrap();
         assert_eq!(
             b"\x53\x61\x6C\x74\x65\x64\x5F\x5F\x53\x61\x23\x11\x23\x56\x74\x12\x68\x4B\xA4\xA2\x6F\xB6\x96\x91\x11\x64\x32\x21\xF9\x2A\xAB\x92".to_vec(),
@@ -310,7 +644,7 @@ mod tests {
     }
     #[test]
     fn can_encrypt_chunks_correctly() {
-        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000).unwrap();
+        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
         let encrypted_chunk1 = openssl_encrypt.encrypt_chunk(&"some".as_bytes().to_vec()).unwrap();
         let encrypted_chunk2 = openssl_encrypt.encrypt_chunk(&" ".as_bytes().to_vec()).unwrap();
         let encrypted_chunk3 = openssl_encrypt.encrypt_chunk(&"data".as_bytes().to_vec()).unwrap();
@@ -322,7 +656,7 @@ mod tests {
     }
     #[test]
     fn can_encrypt_and_decrypt_chunks_correctly() {
-        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000).unwrap();
+        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
         let encrypted_chunk1 = openssl_encrypt.encrypt_chunk(&"some".as_bytes().to_vec()).unwrap();
         let encrypted_chunk2 = openssl_encrypt.encrypt_chunk(&" ".as_bytes().to_vec()).unwrap();
         let encrypted_chunk3 = openssl_encrypt.encrypt_chunk(&"data".as_bytes().to_vec()).unwrap();
@@ -337,14 +671,14 @@ mod tests {
     }
     #[test]
     fn can_encrypt_and_decrypt_correctly() {
-        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000).unwrap();
+        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
         let encrypted_data = openssl_encrypt.encrypt(&"some data".as_bytes().to_vec()).unwrap();
         let decrypted_data = openssl_encrypt.decrypt(&encrypted_data).unwrap();
         assert_eq!(b"some data", &decrypted_data[..]);
     }
     #[test]
     fn can_decrypt_correctly() {
-        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000).unwrap();
+        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
         let encrypted_data = b"\x53\x61\x6c\x74\x65\x64\x5f\x5f\x53\x61\x23\x11\x23\x56\x74\x12\x72\x30\x32\x8f\xca\x92\x3c\x3b\x53\x99\x11\x99\x14\x32\x79\x78".to_vec();
         let decrypted_data = openssl_encrypt.decrypt(&encrypted_data).unwrap();
 
@@ -352,7 +686,7 @@ mod tests {
     }
     #[test]
     fn can_decrypt_128_correctly() {
-        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_128_cbc(), 10000).unwrap();
+        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_128_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
         let encrypted_data = b"\x53\x61\x6C\x74\x65\x64\x5F\x5F\x53\x61\x23\x11\x23\x56\x74\x12\x68\x4B\xA4\xA2\x6F\xB6\x96\x91\x11\x64\x32\x21\xF9\x2A\xAB\x92".to_vec();
         let decrypted_data = openssl_encrypt.decrypt(&encrypted_data).unwrap();
 
@@ -360,7 +694,7 @@ mod tests {
     }
     #[test]
     fn can_decrypt_chunks_correctly() {
-        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000).unwrap();
+        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
         let decrypted_chunk1 = openssl_encrypt.decrypt_chunk(&b"\x53\x61\x6c\x74\x65\x64\x5f\x5f\x53\x61\x23\x11\x23\x56\x74\x12\x72".to_vec()).unwrap();
         let decrypted_chunk2 = openssl_encrypt.decrypt_chunk(&b"\x30\x32\x8f\xca\x92\x3c\x3b\x53\x99\x11".to_vec()).unwrap();
         let decrypted_chunk3 = openssl_encrypt.decrypt_chunk(&b"\x99\x14\x32\x79\x78".to_vec()).unwrap();
@@ -370,4 +704,136 @@ mod tests {
             [&decrypted_chunk1[..], &decrypted_chunk2[..], &decrypted_chunk3[..], &decrypted_final_chunk[..]].concat()
         );
     }
+    #[test]
+    fn can_decrypt_data_with_unknown_salt() {
+        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
+        let encrypted_data = openssl_encrypt.encrypt(&"some data".as_bytes().to_vec()).unwrap();
+
+        let mut openssl_decrypt = OpensslEnc::new_for_decrypt("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
+        let decrypted_data = openssl_decrypt.decrypt(&encrypted_data).unwrap();
+        assert_eq!(b"some data", &decrypted_data[..]);
+    }
+    #[test]
+    fn can_encrypt_and_decrypt_with_legacy_bytes_to_key() {
+        let kdf_mode = KdfMode::LegacyBytesToKey { digest: Digest::Sha256 };
+        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, kdf_mode).unwrap();
+        let encrypted_data = openssl_encrypt.encrypt(&"some data".as_bytes().to_vec()).unwrap();
+        let decrypted_data = openssl_encrypt.decrypt(&encrypted_data).unwrap();
+        assert_eq!(b"some data", &decrypted_data[..]);
+    }
+    #[test]
+    fn md5_is_rejected_as_a_pbkdf2_digest() {
+        let kdf_mode = KdfMode::Pbkdf2 { digest: Digest::Md5 };
+        let result = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, kdf_mode);
+        assert!(result.is_err());
+    }
+    #[test]
+    fn can_decrypt_chunks_with_unknown_salt() {
+        let mut openssl_decrypt = OpensslEnc::new_for_decrypt("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
+        let decrypted_chunk1 = openssl_decrypt.decrypt_chunk(&b"\x53\x61\x6c\x74\x65\x64\x5f\x5f\x53\x61\x23\x11\x23\x56\x74\x12\x72".to_vec()).unwrap();
+        let decrypted_chunk2 = openssl_decrypt.decrypt_chunk(&b"\x30\x32\x8f\xca\x92\x3c\x3b\x53\x99\x11".to_vec()).unwrap();
+        let decrypted_chunk3 = openssl_decrypt.decrypt_chunk(&b"\x99\x14\x32\x79\x78".to_vec()).unwrap();
+        let decrypted_final_chunk = openssl_decrypt.decrypter_finalize().unwrap();
+        assert_eq!(
+            "some data".as_bytes().to_vec(),
+            [&decrypted_chunk1[..], &decrypted_chunk2[..], &decrypted_chunk3[..], &decrypted_final_chunk[..]].concat()
+        );
+    }
+    #[test]
+    fn can_encrypt_and_decrypt_with_aes_gcm() {
+        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_gcm(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
+        let encrypted_data = openssl_encrypt.encrypt(&"some data".as_bytes().to_vec()).unwrap();
+        let decrypted_data = openssl_encrypt.decrypt(&encrypted_data).unwrap();
+        assert_eq!(b"some data", &decrypted_data[..]);
+    }
+    #[test]
+    fn can_encrypt_and_decrypt_gcm_chunks_with_aad() {
+        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_gcm(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
+        openssl_encrypt.set_aad(b"associated data");
+        let encrypted_chunk1 = openssl_encrypt.encrypt_chunk(&"some".as_bytes().to_vec()).unwrap();
+        let encrypted_chunk2 = openssl_encrypt.encrypt_chunk(&" data".as_bytes().to_vec()).unwrap();
+        let encrypted_final_chunk = openssl_encrypt.encrypter_finalize().unwrap();
+        let encrypted_data = [&encrypted_chunk1[..], &encrypted_chunk2[..], &encrypted_final_chunk[..]].concat();
+
+        let mut openssl_decrypt = OpensslEnc::new_for_decrypt("password".to_string(), Cipher::aes_256_gcm(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
+        openssl_decrypt.set_aad(b"associated data");
+        let mut decrypted_data = Vec::new();
+        let (first_chunk, rest) = encrypted_data.split_at(17);
+        decrypted_data.extend(openssl_decrypt.decrypt_chunk(&first_chunk.to_vec()).unwrap());
+        for chunk in rest.chunks(3) {
+            decrypted_data.extend(openssl_decrypt.decrypt_chunk(&chunk.to_vec()).unwrap());
+        }
+        decrypted_data.extend(openssl_decrypt.decrypter_finalize().unwrap());
+        assert_eq!(b"some data", &decrypted_data[..]);
+    }
+    #[test]
+    fn gcm_decrypt_fails_when_ciphertext_is_tampered_with() {
+        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_gcm(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
+        let mut encrypted_data = openssl_encrypt.encrypt(&"some data".as_bytes().to_vec()).unwrap();
+        let last = encrypted_data.len() - 1;
+        encrypted_data[last] ^= 0xff;
+
+        let mut openssl_decrypt = OpensslEnc::new_for_decrypt("password".to_string(), Cipher::aes_256_gcm(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
+        let result = openssl_decrypt.decrypt(&encrypted_data);
+        assert!(result.is_err());
+    }
+    #[test]
+    fn can_encrypt_and_decrypt_with_a_pinned_salt() {
+        let salt_hex = "5361231123567412";
+        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 })
+            .unwrap()
+            .with_salt(salt_hex)
+            .unwrap();
+        let encrypted_data = openssl_encrypt.encrypt(&"some data".as_bytes().to_vec()).unwrap();
+        assert_eq!(
+            b"\x53\x61\x6c\x74\x65\x64\x5f\x5f\x53\x61\x23\x11\x23\x56\x74\x12\x72\x30\x32\x8f\xca\x92\x3c\x3b\x53\x99\x11\x99\x14\x32\x79\x78".to_vec(),
+            encrypted_data
+        );
+
+        let mut openssl_decrypt = OpensslEnc::new_for_decrypt("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
+        let decrypted_data = openssl_decrypt.decrypt(&encrypted_data).unwrap();
+        assert_eq!(b"some data", &decrypted_data[..]);
+    }
+    #[test]
+    fn can_encrypt_and_decrypt_with_a_raw_key_and_iv() {
+        let key = vec![0x11; 32];
+        let iv = vec![0x22; 16];
+        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 })
+            .unwrap()
+            .with_raw_key_iv(&key, &iv)
+            .unwrap()
+            .no_salt()
+            .unwrap();
+        let encrypted_data = openssl_encrypt.encrypt(&"some data".as_bytes().to_vec()).unwrap();
+
+        let mut openssl_decrypt = OpensslEnc::new_for_decrypt("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 })
+            .unwrap()
+            .with_raw_key_iv(&key, &iv)
+            .unwrap()
+            .no_salt()
+            .unwrap();
+        let decrypted_data = openssl_decrypt.decrypt(&encrypted_data).unwrap();
+        assert_eq!(b"some data", &decrypted_data[..]);
+    }
+    #[test]
+    fn can_encrypt_and_decrypt_with_no_salt() {
+        let mut openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 })
+            .unwrap()
+            .no_salt()
+            .unwrap();
+        let encrypted_data = openssl_encrypt.encrypt(&"some data".as_bytes().to_vec()).unwrap();
+
+        let mut openssl_decrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 })
+            .unwrap()
+            .no_salt()
+            .unwrap();
+        let decrypted_data = openssl_decrypt.decrypt(&encrypted_data).unwrap();
+        assert_eq!(b"some data", &decrypted_data[..]);
+    }
+    #[test]
+    fn with_raw_key_iv_rejects_wrong_length_key() {
+        let openssl_encrypt = OpensslEnc::new("password".to_string(), Cipher::aes_256_cbc(), 10000, KdfMode::Pbkdf2 { digest: Digest::Sha256 }).unwrap();
+        let result = openssl_encrypt.with_raw_key_iv(&vec![0u8; 10], &vec![0u8; 16]);
+        assert!(result.is_err());
+    }
 }